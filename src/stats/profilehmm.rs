@@ -1,130 +1,639 @@
-// Copyright 2014-2016 Johannes Köster.
-// Licensed under the MIT license (http://opensource.org/licenses/MIT)
-// This file may not be copied, modified, or distributed
-// except according to those terms.
-
-//! Profile Hidden Markov Model. Can be used for construct profile of multiple sequence alignments
-
-use stats::LogProb;
-
-
-pub struct ProfileHMM {
-    /// number of possible observations
-    pub observation_count: usize,
-    /// probability that x is the initial state
-    pub initial_states_prob: Vec<LogProb>,
-    /// probability of transiting from state x to y
-    pub state_transitions: Vec<Vec<LogProb>>,
-    /// probability of emiting observation y at state x
-    pub emission_matrix: Vec<Vec<LogProb>>
-}
-
-impl ProfileHMM {
-    pub fn new() -> Self {
-        ProfileHMM {
-            observation_count: 0,
-            initial_states_prob: Vec::new(),
-            state_transitions: Vec::new(),
-            emission_matrix: Vec::new()
-        }
-    }
-
-    pub fn forward_algorithm(&self, ref observations: Vec<usize>, sequence_prob: &mut LogProb) -> Vec<Vec<LogProb>> {
-        let mut forward_table = vec![vec![LogProb::ln_zero(); self.initial_states_prob.len()]; observations.len()];
-        let state_count = self.initial_states_prob.len();
-        for time in 0..observations.len() {
-            for state in 0..state_count {
-                if time == 0 {
-                    forward_table[time][state] = self.initial_states_prob[state];
-                } else {
-                    for prev_state in 0..state_count {
-                        forward_table[time][state] = forward_table[time][state].ln_add_exp(forward_table[time - 1][prev_state] + self.state_transitions[prev_state][state]);
-                    }
-                }
-                forward_table[time][state] = forward_table[time][state] + self.emission_matrix[state][observations[time]];
-            }
-        }
-        *sequence_prob = LogProb::ln_zero();
-        for state in 0..state_count {
-            *sequence_prob = sequence_prob.ln_add_exp(forward_table[state][state_count - 1] + self.state_transitions[state][state_count]);
-        }
-        forward_table
-    }
-
-
-    pub fn backward(&self, ref observations: Vec<usize>) -> Vec<Vec<LogProb>> {
-        let mut backward_table = vec![vec![LogProb::ln_zero(); self.initial_states_prob.len()]; observations.len()];
-        let state_count = self.initial_states_prob.len();
-        for time in (0..observations.len()).rev() {
-            for state in 0..self.initial_states_prob.len() {
-                if time + 1 == observations.len() {
-                    backward_table[time][state] = LogProb::ln_one();
-                } else {
-                    for next_state in 0..state_count {
-                        backward_table[time][state] = backward_table[time][state].ln_add_exp(backward_table[time + 1][next_state] + self.state_transitions[state][next_state] + self.emission_matrix[next_state][observations[time + 1]]);
-                    }
-                }
-            }
-        }
-        backward_table
-    }
-
-    pub fn viterbi(&self, ref observations: Vec<usize>, sequence_prob: &mut LogProb) -> Vec<usize> {
-        //Viterbi Reader's Guide:
-        //The term "Winning" transition means the transition selected to be taken by the engine
-        //The term scoring is to multiply a transition by it's correspondance i the Emission Matrix
-        //path_finder[k][i]=MAX(all states:l) {Score[l][i-1] X weight (l,k,i-1)} i:Column, k:Node Serial
-        let mut path_finder: Vec<Vec<LogProb>> = Vec::new(); //path_finder is the memory used for dynamic programming
-
-        let mut previous_state: Vec<Vec<usize>> = Vec::new();
-        let state_count = self.initial_states_prob.len(); //Number of states
-        //Loop handling the first column
-        let mut path_segment: Vec<LogProb> = Vec::new(); //In order to fill a Vector or Vectors we need a temp vector to push
-        previous_state.push(Vec::new());
-        for i in 0..state_count {
-            //The "winning" segment is certainly the ith
-            path_segment.push(self.initial_states_prob[i] + self.emission_matrix[i][observations[0]]);
-        }
-        path_finder.push(path_segment); //<= Like here
-
-        //Loop handling the other columns
-        //For each state
-        for i in 1..observations.len() {
-            //The parameter is pushed here and updated later to ensure the equality of parameters and request for observed states
-            path_segment = Vec::new(); //renewing path_segment
-            previous_state.push(Vec::new());
-            for j in 0..state_count { //each state-*
-                path_segment.push(LogProb::ln_zero());
-                previous_state[i].push(0);
-                for k in 0..state_count { //*-is compared to the other states
-                    //value of the transition=the probability of the previous value X transition probability to this value
-                    let tmp = path_finder[i - 1][k] + self.state_transitions[k][j];
-                    if tmp > path_segment[j] {
-                        previous_state[i][j] = k; //Registering Path For result_states
-                        path_segment[j] = tmp; //Registering Path Value for further path finding
-                    }
-                }
-                path_segment[j] = path_segment[j] + self.emission_matrix[j][observations[i]]; //Scoring the "winning" transition
-            }
-            path_finder.push(path_segment); //2D Code fill process
-        }
-        let mut result_states = vec![0; observations.len()]; //pushing a value to keep editing it
-        *sequence_prob = LogProb::ln_zero();
-        for i in 0..state_count {//Finding Maximum path by iteratively searching the final entry in the path_finder
-            if path_finder[observations.len() - 1][i] >= *sequence_prob {
-                result_states[observations.len() - 1] = i;
-                *sequence_prob = path_finder[observations.len() - 1][i];
-            }
-        }
-        for i in (0..observations.len() - 1).rev() {
-            result_states[i] = previous_state[i + 1][result_states[i + 1]];
-        }
-        result_states
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use stats::{Prob, LogProb};
-}
+// Copyright 2014-2016 Johannes Köster.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Profile Hidden Markov Model. Can be used for construct profile of multiple sequence alignments
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::marker::PhantomData;
+
+use stats::LogProb;
+
+/// Pseudocount-seeded tallies of the Plan7 M/I/D moves made between two consensus columns.
+struct TransitionCounts {
+    mm: f64, mi: f64, md: f64,
+    im: f64, ii: f64,
+    dm: f64, dd: f64
+}
+
+impl TransitionCounts {
+    fn with_pseudocounts() -> Self {
+        TransitionCounts { mm: 1.0, mi: 1.0, md: 1.0, im: 1.0, ii: 1.0, dm: 1.0, dd: 1.0 }
+    }
+}
+
+/// An emission model: the log-probability of observing `observation` while in `state`.
+pub trait Emission<O> {
+    fn log_prob(&self, state: usize, observation: &O) -> LogProb;
+}
+
+/// The classic categorical emission model: a dense `matrix[state][observation]` of
+/// log-probabilities, for observations drawn from a small, fixed alphabet.
+pub struct CategoricalEmission {
+    pub matrix: Vec<Vec<LogProb>>
+}
+
+impl Emission<usize> for CategoricalEmission {
+    fn log_prob(&self, state: usize, observation: &usize) -> LogProb {
+        self.matrix[state][*observation]
+    }
+}
+
+/// A Gaussian emission model for real-valued signal, with a per-state mean and variance.
+pub struct GaussianEmission {
+    pub mean: Vec<f64>,
+    pub variance: Vec<f64>
+}
+
+impl Emission<f64> for GaussianEmission {
+    fn log_prob(&self, state: usize, observation: &f64) -> LogProb {
+        let mean = self.mean[state];
+        let variance = self.variance[state];
+        let diff = observation - mean;
+        LogProb(-0.5 * ((2.0 * PI * variance).ln() + diff * diff / variance))
+    }
+}
+
+/// A negative-binomial emission model for over-dispersed count data (e.g. sequencing
+/// read-count bins), parameterized per state by a shape `alpha` and inverse-scale `beta`.
+pub struct NegativeBinomialEmission {
+    pub shape: Vec<f64>,
+    pub inverse_scale: Vec<f64>
+}
+
+impl Emission<u64> for NegativeBinomialEmission {
+    fn log_prob(&self, state: usize, observation: &u64) -> LogProb {
+        let k = *observation as f64;
+        let alpha = self.shape[state];
+        let beta = self.inverse_scale[state];
+        let ln_coefficient = ln_gamma(k + alpha) - ln_gamma(k + 1.0) - ln_gamma(alpha);
+        let ln_p = alpha * (beta / (beta + 1.0)).ln() + k * (1.0 / (beta + 1.0)).ln();
+        LogProb(ln_coefficient + ln_p)
+    }
+}
+
+/// Lanczos approximation of the natural logarithm of the Gamma function.
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.99999999999980993,
+        676.5203681218851,
+        -1259.1392167224028,
+        771.32342877765313,
+        -176.61502916214059,
+        12.507343278686905,
+        -0.13857109526572012,
+        9.9843695780195716e-6,
+        1.5056327351493116e-7
+    ];
+
+    if x < 0.5 {
+        // Reflection formula: Gamma(x) * Gamma(1 - x) = pi / sin(pi * x)
+        (PI / (PI * x).sin()).ln() - ln_gamma(1.0 - x)
+    } else {
+        let x = x - 1.0;
+        let mut a = COEFFICIENTS[0];
+        let t = x + G + 0.5;
+        for (i, &coefficient) in COEFFICIENTS.iter().enumerate().skip(1) {
+            a += coefficient / (x + i as f64);
+        }
+        0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + a.ln()
+    }
+}
+
+pub struct ProfileHMM<O, E: Emission<O>> {
+    /// number of possible observations
+    pub observation_count: usize,
+    /// probability that x is the initial state
+    pub initial_states_prob: Vec<LogProb>,
+    /// probability of transiting from state x to y; column (and row) `initial_states_prob.len()`
+    /// is the dedicated end state every path must transit into to terminate
+    pub state_transitions: Vec<Vec<LogProb>>,
+    /// whether state x is silent (a delete state), i.e. emits no observation
+    pub silent: Vec<bool>,
+    /// the emission model scoring observations against states
+    pub emission: E,
+    _observation: PhantomData<O>
+}
+
+impl<O, E: Emission<O>> ProfileHMM<O, E> {
+    pub fn new(emission: E) -> Self {
+        ProfileHMM {
+            observation_count: 0,
+            initial_states_prob: Vec::new(),
+            state_transitions: Vec::new(),
+            silent: Vec::new(),
+            emission,
+            _observation: PhantomData
+        }
+    }
+
+    /// Whether `state` is silent, i.e. does not emit an observation.
+    fn is_silent(&self, state: usize) -> bool {
+        self.silent.get(state).cloned().unwrap_or(false)
+    }
+
+    pub fn forward_algorithm(&self, observations: &[O], sequence_prob: &mut LogProb) -> Vec<Vec<LogProb>> {
+        let mut forward_table = vec![vec![LogProb::ln_zero(); self.initial_states_prob.len()]; observations.len()];
+        let state_count = self.initial_states_prob.len();
+        // A non-silent state consumes observations[time], so its predecessors -- real or
+        // silent -- are read off the previous time step, already fully resolved. A silent
+        // state consumes nothing, so it instead chains off predecessors at this *same* time
+        // step; states are assumed numbered so that a silent state's predecessors always have
+        // a lower index, making them available already by the time we get here.
+        for time in 0..observations.len() {
+            for state in 0..state_count {
+                forward_table[time][state] = if time == 0 { self.initial_states_prob[state] } else { LogProb::ln_zero() };
+                if self.is_silent(state) {
+                    for prev_state in 0..state {
+                        forward_table[time][state] = forward_table[time][state].ln_add_exp(forward_table[time][prev_state] + self.state_transitions[prev_state][state]);
+                    }
+                } else {
+                    if time > 0 {
+                        for prev_state in 0..state_count {
+                            forward_table[time][state] = forward_table[time][state].ln_add_exp(forward_table[time - 1][prev_state] + self.state_transitions[prev_state][state]);
+                        }
+                    }
+                    forward_table[time][state] = forward_table[time][state] + self.emission.log_prob(state, &observations[time]);
+                }
+            }
+        }
+        // Every path must end by transiting into the dedicated end state.
+        let end = state_count;
+        let last_time = observations.len() - 1;
+        *sequence_prob = LogProb::ln_zero();
+        for state in 0..state_count {
+            *sequence_prob = sequence_prob.ln_add_exp(forward_table[last_time][state] + self.state_transitions[state][end]);
+        }
+        forward_table
+    }
+
+
+    pub fn backward(&self, observations: &[O]) -> Vec<Vec<LogProb>> {
+        let mut backward_table = vec![vec![LogProb::ln_zero(); self.initial_states_prob.len()]; observations.len()];
+        let state_count = self.initial_states_prob.len();
+        let end = state_count;
+        // Mirrors the delete-state chaining in `forward_algorithm`, walked in reverse: states
+        // are processed highest-numbered first so that a transition into a silent
+        // `next_state` can use the already-resolved `backward_table[time][next_state]`.
+        for time in (0..observations.len()).rev() {
+            for state in (0..state_count).rev() {
+                let last = time + 1 == observations.len();
+                if last {
+                    // Every path must end by transiting into the dedicated end state, so a
+                    // state's exit probability here is that transition, not a flat 1
+                    // (plus any route through silent states that reaches the end).
+                    backward_table[time][state] = self.state_transitions[state][end];
+                }
+                for next_state in 0..state_count {
+                    let continuation = if self.is_silent(next_state) {
+                        backward_table[time][next_state]
+                    } else if last {
+                        continue;
+                    } else {
+                        self.emission.log_prob(next_state, &observations[time + 1]) + backward_table[time + 1][next_state]
+                    };
+                    backward_table[time][state] = backward_table[time][state].ln_add_exp(self.state_transitions[state][next_state] + continuation);
+                }
+            }
+        }
+        backward_table
+    }
+
+    /// A-posteriori (forward-backward) decoding: the marginal probability of each state at
+    /// each position, plus the per-position argmax (MAP) state, which may differ from the
+    /// Viterbi path since it optimizes each position independently.
+    pub fn posterior_decoding(&self, observations: &[O]) -> (Vec<Vec<LogProb>>, Vec<usize>) {
+        let mut sequence_prob = LogProb::ln_zero();
+        let forward_table = self.forward_algorithm(observations, &mut sequence_prob);
+        let backward_table = self.backward(observations);
+        let state_count = self.initial_states_prob.len();
+
+        let mut posterior = vec![vec![LogProb::ln_zero(); state_count]; observations.len()];
+        let mut map_states = vec![0; observations.len()];
+        for time in 0..observations.len() {
+            let mut best_state = 0;
+            for state in 0..state_count {
+                posterior[time][state] = forward_table[time][state] + backward_table[time][state] - sequence_prob;
+                if posterior[time][state] > posterior[time][best_state] {
+                    best_state = state;
+                }
+            }
+            map_states[time] = best_state;
+        }
+
+        (posterior, map_states)
+    }
+
+    pub fn viterbi(&self, observations: &[O], sequence_prob: &mut LogProb) -> Vec<usize> {
+        // `previous[time][state]` is the (time, state) of the predecessor on the best path,
+        // which may be a silent state at the same `time` rather than `time - 1`. `None` marks
+        // a path start.
+        let state_count = self.initial_states_prob.len();
+        let mut path_finder: Vec<Vec<LogProb>> = vec![vec![LogProb::ln_zero(); state_count]; observations.len()];
+        let mut previous: Vec<Vec<Option<(usize, usize)>>> = vec![vec![None; state_count]; observations.len()];
+
+        for time in 0..observations.len() {
+            for state in 0..state_count {
+                let mut best = if time == 0 { self.initial_states_prob[state] } else { LogProb::ln_zero() };
+                let mut best_pred = None;
+                if self.is_silent(state) {
+                    for prev in 0..state {
+                        let score = path_finder[time][prev] + self.state_transitions[prev][state];
+                        if score > best {
+                            best = score;
+                            best_pred = Some((time, prev));
+                        }
+                    }
+                } else {
+                    if time > 0 {
+                        for prev in 0..state_count {
+                            let score = path_finder[time - 1][prev] + self.state_transitions[prev][state];
+                            if score > best {
+                                best = score;
+                                best_pred = Some((time - 1, prev));
+                            }
+                        }
+                    }
+                    best = best + self.emission.log_prob(state, &observations[time]);
+                }
+                path_finder[time][state] = best;
+                previous[time][state] = best_pred;
+            }
+        }
+
+        // As in `forward_algorithm`, every path must terminate into the dedicated end state.
+        let end = state_count;
+        let last_time = observations.len() - 1;
+        *sequence_prob = LogProb::ln_zero();
+        let mut best_final_state = 0;
+        for state in 0..state_count {
+            let score = path_finder[last_time][state] + self.state_transitions[state][end];
+            if score >= *sequence_prob {
+                *sequence_prob = score;
+                best_final_state = state;
+            }
+        }
+
+        // Record only non-silent states: a delete state doesn't own an observation.
+        let mut result_states = vec![0; observations.len()];
+        let mut cursor = Some((last_time, best_final_state));
+        while let Some((time, state)) = cursor {
+            if !self.is_silent(state) {
+                result_states[time] = state;
+            }
+            cursor = previous[time][state];
+        }
+        result_states
+    }
+}
+
+/// Construction and training are only implemented for the categorical emission model:
+/// both need to re-estimate or count into a dense `matrix[state][observation]`, which the
+/// `Emission` trait does not expose generically for arbitrary emission models.
+impl ProfileHMM<usize, CategoricalEmission> {
+    /// Build a Plan7-style profile HMM (Match/Insert/Delete states per consensus column)
+    /// directly from a multiple sequence alignment. A column becomes a consensus Match
+    /// column when fewer than `match_threshold` of its rows are gaps; all other columns are
+    /// treated as insertions relative to the profile.
+    pub fn from_msa(alignment: &[Vec<Option<u8>>], match_threshold: f64) -> Self {
+        let alphabet = ProfileHMM::<usize, CategoricalEmission>::alphabet(alignment);
+        let observation_count = alphabet.len();
+        let num_rows = alignment.len();
+        let num_cols = if num_rows == 0 { 0 } else { alignment[0].len() };
+
+        let match_columns: Vec<usize> = (0..num_cols)
+            .filter(|&col| {
+                let gaps = alignment.iter().filter(|row| row[col].is_none()).count();
+                (gaps as f64) / (num_rows as f64) < match_threshold
+            })
+            .collect();
+        let num_match_states = match_columns.len();
+        let background = ProfileHMM::<usize, CategoricalEmission>::background_frequencies(alignment, &alphabet);
+
+        // States are laid out column-major as (M, I, D) triples.
+        let state = |k: usize| 3 * k;
+        let m = |k: usize| state(k);
+        let i = |k: usize| state(k) + 1;
+        let d = |k: usize| state(k) + 2;
+        let state_count = 3 * num_match_states;
+
+        // `state_transitions` carries one extra column (and row, kept unused) for the
+        // dedicated end state at index `state_count`, per the Plan7 termination convention.
+        let end = state_count;
+        let mut hmm = ProfileHMM::new(CategoricalEmission { matrix: vec![vec![LogProb::ln_zero(); observation_count]; state_count] });
+        hmm.observation_count = observation_count;
+        hmm.initial_states_prob = vec![LogProb::ln_zero(); state_count];
+        hmm.state_transitions = vec![vec![LogProb::ln_zero(); state_count + 1]; state_count + 1];
+        hmm.silent = vec![false; state_count];
+
+        if num_match_states == 0 {
+            return hmm;
+        }
+
+        hmm.initial_states_prob[m(0)] = LogProb::ln_one();
+
+        for k in 0..num_match_states {
+            hmm.silent[d(k)] = true;
+
+            let col = match_columns[k];
+            let mut match_counts = vec![1.0; observation_count]; // Laplace pseudocount
+            for row in alignment {
+                if let Some(residue) = row[col] {
+                    match_counts[alphabet[&residue]] += 1.0;
+                }
+            }
+            let total: f64 = match_counts.iter().sum();
+            for (obs, &count) in match_counts.iter().enumerate() {
+                hmm.emission.matrix[m(k)][obs] = LogProb((count / total).ln());
+            }
+            for (obs, &freq) in background.iter().enumerate() {
+                hmm.emission.matrix[i(k)][obs] = LogProb(freq.ln());
+            }
+        }
+
+        for k in 0..num_match_states - 1 {
+            let counts = ProfileHMM::<usize, CategoricalEmission>::count_transitions(alignment, match_columns[k], match_columns[k + 1]);
+
+            let m_total = counts.mm + counts.mi + counts.md;
+            hmm.state_transitions[m(k)][m(k + 1)] = LogProb((counts.mm / m_total).ln());
+            hmm.state_transitions[m(k)][i(k)] = LogProb((counts.mi / m_total).ln());
+            hmm.state_transitions[m(k)][d(k + 1)] = LogProb((counts.md / m_total).ln());
+
+            let i_total = counts.im + counts.ii;
+            hmm.state_transitions[i(k)][m(k + 1)] = LogProb((counts.im / i_total).ln());
+            hmm.state_transitions[i(k)][i(k)] = LogProb((counts.ii / i_total).ln());
+
+            let d_total = counts.dm + counts.dd;
+            hmm.state_transitions[d(k)][m(k + 1)] = LogProb((counts.dm / d_total).ln());
+            hmm.state_transitions[d(k)][d(k + 1)] = LogProb((counts.dd / d_total).ln());
+        }
+
+        // Any of the three states of the last consensus column may terminate the path.
+        let last = num_match_states - 1;
+        hmm.state_transitions[m(last)][end] = LogProb::ln_one();
+        hmm.state_transitions[i(last)][end] = LogProb::ln_one();
+        hmm.state_transitions[d(last)][end] = LogProb::ln_one();
+
+        hmm
+    }
+
+    /// Observed alphabet symbols across the whole alignment, mapped to emission indices.
+    fn alphabet(alignment: &[Vec<Option<u8>>]) -> HashMap<u8, usize> {
+        let mut symbols: Vec<u8> = alignment.iter()
+            .flat_map(|row| row.iter().filter_map(|&cell| cell))
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        symbols.into_iter().enumerate().map(|(idx, symbol)| (symbol, idx)).collect()
+    }
+
+    /// Background residue frequencies across the whole alignment, with a Laplace pseudocount.
+    fn background_frequencies(alignment: &[Vec<Option<u8>>], alphabet: &HashMap<u8, usize>) -> Vec<f64> {
+        let mut counts = vec![1.0; alphabet.len()];
+        for row in alignment {
+            for &cell in row {
+                if let Some(residue) = cell {
+                    counts[alphabet[&residue]] += 1.0;
+                }
+            }
+        }
+        let total: f64 = counts.iter().sum();
+        counts.into_iter().map(|count| count / total).collect()
+    }
+
+    /// Count M/I/D moves made by every aligned row between two consecutive Match columns,
+    /// with a pseudocount of one added to every kind of move.
+    fn count_transitions(alignment: &[Vec<Option<u8>>], from_col: usize, to_col: usize) -> TransitionCounts {
+        let mut counts = TransitionCounts::with_pseudocounts();
+        for row in alignment {
+            let from_match = row[from_col].is_some();
+            let to_match = row[to_col].is_some();
+            let inserted = (from_col + 1..to_col).filter(|&col| row[col].is_some()).count();
+
+            if inserted > 0 {
+                if from_match {
+                    counts.mi += 1.0;
+                }
+                counts.ii += (inserted - 1) as f64;
+                if to_match {
+                    counts.im += 1.0;
+                }
+            } else {
+                match (from_match, to_match) {
+                    (true, true) => counts.mm += 1.0,
+                    (true, false) => counts.md += 1.0,
+                    (false, true) => counts.dm += 1.0,
+                    (false, false) => counts.dd += 1.0,
+                }
+            }
+        }
+        counts
+    }
+
+    /// Re-estimate `initial_states_prob`, `state_transitions` and `emission.matrix` from a
+    /// training corpus of observation sequences using Baum-Welch (EM). Iteration stops once
+    /// the total log-likelihood improves by less than `tol`, or after `max_iters`
+    /// iterations. Returns, for each iteration, the total log-likelihood of the corpus under
+    /// the parameters that iteration started from (i.e. before its re-estimation).
+    pub fn baum_welch(&mut self, sequences: &[Vec<usize>], max_iters: usize, tol: f64) -> Vec<f64> {
+        let state_count = self.initial_states_prob.len();
+        let end = state_count;
+        let mut log_likelihood_trace = Vec::new();
+        let mut prev_log_likelihood = f64::NEG_INFINITY;
+
+        for _ in 0..max_iters {
+            let mut gamma_initial_sum = vec![LogProb::ln_zero(); state_count];
+            // Denominator for every outgoing transition of state i, and for its emissions.
+            let mut gamma_sum = vec![LogProb::ln_zero(); state_count];
+            let mut xi_sum = vec![vec![LogProb::ln_zero(); state_count]; state_count];
+            // Posterior mass of state i -> end transitions.
+            let mut xi_end_sum = vec![LogProb::ln_zero(); state_count];
+            let mut emit_sum = vec![vec![LogProb::ln_zero(); self.observation_count]; state_count];
+            let mut total_log_likelihood = LogProb::ln_one();
+
+            for observations in sequences {
+                let mut sequence_prob = LogProb::ln_zero();
+                let forward_table = self.forward_algorithm(observations, &mut sequence_prob);
+                let backward_table = self.backward(observations);
+                total_log_likelihood = total_log_likelihood + sequence_prob;
+                let last_time = observations.len() - 1;
+
+                for time in 0..observations.len() {
+                    for state in 0..state_count {
+                        let gamma = forward_table[time][state] + backward_table[time][state] - sequence_prob;
+                        gamma_sum[state] = gamma_sum[state].ln_add_exp(gamma);
+                        emit_sum[state][observations[time]] = emit_sum[state][observations[time]].ln_add_exp(gamma);
+                        if time == 0 {
+                            gamma_initial_sum[state] = gamma_initial_sum[state].ln_add_exp(gamma);
+                        }
+                        if time == last_time {
+                            let xi_end = forward_table[time][state] + self.state_transitions[state][end] - sequence_prob;
+                            xi_end_sum[state] = xi_end_sum[state].ln_add_exp(xi_end);
+                        }
+                    }
+                    for i in 0..state_count {
+                        for j in 0..state_count {
+                            // Mirrors `backward`: a silent j doesn't consume observations[time + 1].
+                            let continuation = if self.is_silent(j) {
+                                backward_table[time][j]
+                            } else if time == last_time {
+                                continue;
+                            } else {
+                                self.emission.matrix[j][observations[time + 1]] + backward_table[time + 1][j]
+                            };
+                            let xi = forward_table[time][i] + self.state_transitions[i][j] + continuation - sequence_prob;
+                            xi_sum[i][j] = xi_sum[i][j].ln_add_exp(xi);
+                        }
+                    }
+                }
+            }
+
+            let sequence_count = LogProb((sequences.len() as f64).ln());
+            for state in 0..state_count {
+                self.initial_states_prob[state] = gamma_initial_sum[state] - sequence_count;
+                for next_state in 0..state_count {
+                    self.state_transitions[state][next_state] = xi_sum[state][next_state] - gamma_sum[state];
+                }
+                self.state_transitions[state][end] = xi_end_sum[state] - gamma_sum[state];
+                for obs in 0..self.observation_count {
+                    self.emission.matrix[state][obs] = emit_sum[state][obs] - gamma_sum[state];
+                }
+            }
+
+            log_likelihood_trace.push(total_log_likelihood.0);
+            if (total_log_likelihood.0 - prev_log_likelihood).abs() < tol {
+                prev_log_likelihood = total_log_likelihood.0;
+                break;
+            }
+            prev_log_likelihood = total_log_likelihood.0;
+        }
+
+        log_likelihood_trace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stats::{Prob, LogProb};
+
+    fn ln(p: f64) -> LogProb {
+        LogProb(p.ln())
+    }
+
+    /// A 2-state (plus end) categorical toy HMM, small enough to reason about by hand.
+    fn toy_hmm() -> ProfileHMM<usize, CategoricalEmission> {
+        let mut hmm = ProfileHMM::new(CategoricalEmission {
+            matrix: vec![vec![ln(0.9), ln(0.1)], vec![ln(0.2), ln(0.8)]]
+        });
+        hmm.observation_count = 2;
+        hmm.initial_states_prob = vec![ln(0.6), ln(0.4)];
+        hmm.state_transitions = vec![
+            vec![ln(0.7), ln(0.2), ln(0.1)],
+            vec![ln(0.3), ln(0.6), ln(0.1)],
+            vec![LogProb::ln_zero(), LogProb::ln_zero(), LogProb::ln_zero()]
+        ];
+        hmm.silent = vec![false, false];
+        hmm
+    }
+
+    #[test]
+    fn from_msa_rows_are_normalized_probability_distributions() {
+        let alignment = vec![
+            vec![Some(b'A'), Some(b'C')],
+            vec![Some(b'A'), Some(b'G')],
+            vec![Some(b'T'), Some(b'C')]
+        ];
+        let hmm = ProfileHMM::<usize, CategoricalEmission>::from_msa(&alignment, 0.5);
+        let real_state_count = hmm.initial_states_prob.len();
+
+        for row in &hmm.state_transitions[..real_state_count] {
+            let mut total = LogProb::ln_zero();
+            for &p in row {
+                total = total.ln_add_exp(p);
+            }
+            assert!(total.0.abs() < 1e-6);
+        }
+        for state in 0..real_state_count {
+            if hmm.silent[state] {
+                continue;
+            }
+            let mut total = LogProb::ln_zero();
+            for &p in &hmm.emission.matrix[state] {
+                total = total.ln_add_exp(p);
+            }
+            assert!(total.0.abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn delete_states_do_not_consume_an_observation() {
+        // A 2-column alignment has a delete state (d(1)) that can be reached straight from
+        // m(0) and then transits straight to end without consuming a second observation, so a
+        // sequence with only one observation must still get a finite probability.
+        let alignment = vec![
+            vec![Some(b'A'), Some(b'C')],
+            vec![Some(b'A'), Some(b'G')],
+            vec![Some(b'T'), Some(b'C')]
+        ];
+        let hmm = ProfileHMM::<usize, CategoricalEmission>::from_msa(&alignment, 0.5);
+        assert!(hmm.silent[5]); // d(1)
+
+        let mut sequence_prob = LogProb::ln_zero();
+        hmm.forward_algorithm(&[0], &mut sequence_prob);
+        assert!(sequence_prob.0.is_finite());
+    }
+
+    #[test]
+    fn posterior_decoding_rows_sum_to_one() {
+        let hmm = toy_hmm();
+        let observations = vec![0, 1, 0];
+        let (posterior, _) = hmm.posterior_decoding(&observations);
+        for row in &posterior {
+            let mut total = LogProb::ln_zero();
+            for &p in row {
+                total = total.ln_add_exp(p);
+            }
+            assert!(total.0.abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn gaussian_emission_peaks_at_the_mean() {
+        let emission = GaussianEmission { mean: vec![0.0], variance: vec![1.0] };
+        assert!(emission.log_prob(0, &0.0).0 > emission.log_prob(0, &3.0).0);
+    }
+
+    #[test]
+    fn negative_binomial_emission_is_finite_and_decreases_away_from_the_mode() {
+        let emission = NegativeBinomialEmission { shape: vec![5.0], inverse_scale: vec![1.0] };
+        let at_four = emission.log_prob(0, &4).0;
+        let at_forty = emission.log_prob(0, &40).0;
+        assert!(at_four.is_finite() && at_forty.is_finite());
+        assert!(at_four > at_forty);
+    }
+
+    #[test]
+    fn viterbi_path_is_no_more_likely_than_the_full_forward_probability() {
+        let hmm = toy_hmm();
+        let observations = vec![0, 1, 0];
+        let mut forward_prob = LogProb::ln_zero();
+        hmm.forward_algorithm(&observations, &mut forward_prob);
+        let mut viterbi_prob = LogProb::ln_zero();
+        hmm.viterbi(&observations, &mut viterbi_prob);
+        assert!(viterbi_prob.0 <= forward_prob.0 + 1e-9);
+    }
+
+    #[test]
+    fn baum_welch_log_likelihood_does_not_decrease() {
+        let mut hmm = toy_hmm();
+        let sequences = vec![vec![0, 1, 0, 0], vec![1, 1, 0, 1]];
+        let trace = hmm.baum_welch(&sequences, 5, 1e-6);
+        for window in trace.windows(2) {
+            assert!(window[1] >= window[0] - 1e-6);
+        }
+    }
+}